@@ -10,11 +10,11 @@ Bit(s)              Set                                 Unset
 1 version:          previous version.
 2                   7 indicates new distinct version.
 -----
-3 prev_block_hash:  omitted (0 byte field).             new 4 byte hash to follow
+3 prev_block_hash:  omitted (0 byte field).             new 32 byte hash to follow
 4 timestamp:        2 byte offset from previous.        new 4 byte timestamp to follow
 5 nBits:            same as previous (0 byte field).    new 4 byte field to follow
 6 sequence_end:     last header in sequence.            more headers to follow
-7
+7 reserved:         refused by strict decompression.    unused
 
 Uncompressed header structure:
 ---------------------
@@ -33,6 +33,7 @@ import logging
 import struct
 from collections import deque
 from io import BytesIO
+from typing import Optional, Union
 
 
 HEADER_LEN = 80
@@ -45,24 +46,204 @@ MASK_PREV_BLOCK_HASH = 0b1   << 4
 MASK_TIME            = 0b1   << 3
 MASK_NBITS           = 0b1   << 2
 MASK_END             = 0b1   << 1
+MASK_DEFINED = MASK_VERSION | MASK_PREV_BLOCK_HASH | MASK_TIME | MASK_NBITS | MASK_END
 NEW_DISTINCT_VERSION = 7
+# Indices 0 - 6 address cached versions, so the cache must stay below the sentinel
+VERSION_CACHE_LEN = NEW_DISTINCT_VERSION
 
 # Min and Max int values for our 2 byte time offset
 MAX_INT = int((ctypes.c_uint16(-1).value - 1) / 2)
 MIN_INT = - int((ctypes.c_uint16(-1).value + 1) / 2)
 
+# Best and worst case record sizes given by the spec, including the bitfield. Both
+# assume prev_block_hash is omitted; a record which sends it is 32 bytes longer
+SPEC_MIN_RECORD_LEN = 1 + 32 + 2 + 4
+SPEC_MAX_RECORD_LEN = 1 + 4 + 32 + 4 + 4 + 4
+# Size of a record with every field explicit, as when all optimisations are disabled
+FULL_RECORD_LEN = 1 + HEADER_LEN
+
 
 class CompressionError(Exception):
     pass
 
 
+class InsaneFieldError(CompressionError):
+    def __init__(self, field: str, value: int):
+        super().__init__(f"insane {field} value: {value}")
+        self.field = field
+        self.value = value
+
+
+class TipMismatchError(CompressionError):
+    def __init__(self, expected: bytes, actual: bytes):
+        super().__init__(
+            f"decompressed tip {actual[::-1].hex()} does not match "
+            f"expected {expected[::-1].hex()}"
+        )
+        self.expected = expected
+        self.actual = actual
+
+
+class InvalidHashError(CompressionError):
+    def __init__(self, block_hash):
+        super().__init__(
+            f"block hash must be 64 hex characters or 32 bytes, not {block_hash!r}"
+        )
+        self.block_hash = block_hash
+
+
+class TrailingBytesError(CompressionError):
+    def __init__(self, count: int, offset: int):
+        super().__init__(
+            f"{count} trailing bytes at offset {offset} do not form a complete record"
+        )
+        self.count = count
+        self.offset = offset
+
+
+class NonCanonicalError(CompressionError):
+    def __init__(self, header_index: int, field: str):
+        super().__init__(f"header {header_index} has a non-canonical {field} encoding")
+        self.header_index = header_index
+        self.field = field
+
+
+class VersionIndexError(CompressionError):
+    def __init__(self, header_index: int, version_index: int, cached: int):
+        super().__init__(
+            f"header {header_index} refers to version index {version_index} "
+            f"but only {cached} versions are cached"
+        )
+        self.header_index = header_index
+        self.version_index = version_index
+        self.cached = cached
+
+
+class LimitExceededError(CompressionError):
+    def __init__(self, limit: str, maximum: int):
+        super().__init__(f"input exceeds the limit of {maximum} {limit}")
+        self.limit = limit
+        self.maximum = maximum
+
+
 def hash_header(header: bytes):
     return hashlib.sha256(hashlib.sha256(header).digest()).digest()
 
 
-def _compress(in_stream: BytesIO, out_stream: BytesIO):
+def check_sanity(header: bytes):
+    """
+    Check cheap invariants which no real block header violates.
+
+    :raises InsaneFieldError on the first nonsensical field found
+    """
+    (version,) = struct.unpack("<i", header[0:4])
+    if version == 0:
+        raise InsaneFieldError("version", version)
+    (time,) = struct.unpack("<I", header[68:72])
+    if time == 0:
+        raise InsaneFieldError("time", time)
+    (n_bits,) = struct.unpack("<I", header[72:76])
+    # A zero mantissa gives an undefined target
+    if n_bits & 0x007FFFFF == 0:
+        raise InsaneFieldError("nBits", n_bits)
+
+
+def time_offset(prev_time: bytes, next_time: bytes) -> Optional[bytes]:
+    """
+    Encode a 4 byte time field as a 2 byte offset from the previous header's.
+
+    :return the 2 byte offset field, or None if the offset does not fit
+    """
+    (prev,) = struct.unpack("<I", prev_time)
+    (next_,) = struct.unpack("<I", next_time)
+    offset = next_ - prev
+    if MIN_INT <= offset <= MAX_INT:
+        return struct.pack("<h", offset)
+    return None
+
+
+def apply_time_offset(prev_time: bytes, offset: bytes) -> bytes:
+    """
+    Decode a 2 byte offset field against the previous header's 4 byte time field.
+
+    :raises CompressionError if the result does not fit in a 4 byte time field
+    """
+    (prev,) = struct.unpack("<I", prev_time)
+    (delta,) = struct.unpack("<h", offset)
+    time = prev + delta
+    if not 0 <= time <= 0xFFFFFFFF:
+        raise CompressionError(f"time offset {delta} from {prev} is out of range")
+    return struct.pack("<I", time)
+
+
+def record_length(bitfield: int) -> int:
+    """
+    Length of the compressed record which follows a bitfield, excluding the bitfield.
+    """
+    # Merkle_root and nonce are always sent in full
+    length = 32 + 4
+    if bitfield >> 5 == NEW_DISTINCT_VERSION:
+        length += 4
+    if not bitfield & MASK_PREV_BLOCK_HASH:
+        length += 32
+    length += 2 if bitfield & MASK_TIME else 4
+    if not bitfield & MASK_NBITS:
+        length += 4
+    return length
+
+
+def self_contained(bitfield: int) -> bool:
+    """
+    Whether the record following a bitfield holds every field explicitly, and so decodes
+    without the previous header.
+    """
+    return bitfield >> 5 == NEW_DISTINCT_VERSION and not bitfield & (
+        MASK_PREV_BLOCK_HASH | MASK_TIME | MASK_NBITS
+    )
+
+
+def _internal_hash(block_hash) -> bytes:
+    """
+    Block hashes given as a str are display hex, and reversed into internal byte order.
+    Hashes given as bytes are taken to already be in internal byte order.
+
+    :raises InvalidHashError if block_hash is neither
+    """
+    if isinstance(block_hash, str):
+        try:
+            internal = bytes.fromhex(block_hash)[::-1]
+        except ValueError:
+            raise InvalidHashError(block_hash) from None
+    elif isinstance(block_hash, (bytes, bytearray)):
+        internal = bytes(block_hash)
+    else:
+        raise InvalidHashError(block_hash)
+    if len(internal) != 32:
+        raise InvalidHashError(block_hash)
+    return internal
+
+
+def _read_exact(in_stream: BytesIO, length: int, record_start: int) -> bytes:
+    data = in_stream.read(length)
+    if len(data) < length:
+        raise TrailingBytesError(in_stream.tell() - record_start, record_start)
+    return data
+
+
+def _compress(
+    in_stream: BytesIO,
+    out_stream: BytesIO,
+    sanity: bool,
+    allow_partial: bool,
+    disable_version_cache: bool,
+    disable_prev_hash_omission: bool,
+    disable_time_offset: bool,
+    disable_nbits_omission: bool,
+    first_full: bool,
+    version_cache_len: int = VERSION_CACHE_LEN,
+):
     # Init the previous versions deque
-    prev_versions = deque(maxlen=7)
+    prev_versions = deque(maxlen=version_cache_len)
     first = True
 
     while True:
@@ -72,7 +253,23 @@ def _compress(in_stream: BytesIO, out_stream: BytesIO):
         # Rewind for the next iteration to read prev_header from the right place
         in_stream.seek(in_pos_start + HEADER_LEN)
 
+        # A partial header can only be the last thing in the stream
+        if len(prev_header) < HEADER_LEN:
+            partial, partial_start = prev_header, in_pos_start
+        else:
+            partial, partial_start = next_header, in_pos_start + HEADER_LEN
+        if 0 < len(partial) < HEADER_LEN:
+            if not allow_partial:
+                raise TrailingBytesError(len(partial), partial_start)
+            logger.warning(
+                f"dropping {len(partial)} trailing bytes at offset {partial_start}"
+            )
+            next_header = b""
+
         if not next_header:
+            # Nothing was compressed so there is no bitfield to update
+            if first:
+                break
             # Return to the beginning of last header
             out_stream.seek(out_pos_start)
             # Read the bitfield and set sequence_end bit
@@ -92,15 +289,29 @@ def _compress(in_stream: BytesIO, out_stream: BytesIO):
         # Initialise an empty bitfield
         bitfield = 0b00000000
 
+        first_record = first
+        # The first record is sent in full when the receiver may not hold prev_header
+        full = first_record and first_full
+
         # On first iteration we add prev_header to the index before beginning
         if first:
+            if sanity:
+                check_sanity(prev_header)
             prev_versions.appendleft(prev_header[0:4])
             first = False
 
+        if sanity:
+            check_sanity(next_header)
+
         # Version
-        if next_header[0:4] in prev_versions:
+        if next_header[0:4] in prev_versions and not (disable_version_cache or full):
+            v_index = prev_versions.index(next_header[0:4])
+            if v_index >= NEW_DISTINCT_VERSION:
+                raise CompressionError(
+                    f"version index {v_index} collides with the new version sentinel"
+                )
             # Add the index of the previous version to the bitfield
-            bitfield = bitfield ^ (prev_versions.index(next_header[0:4]) << 5)
+            bitfield = bitfield ^ (v_index << 5)
         else:
             prev_versions.appendleft(next_header[0:4])
             # Update the bitfield to indicate new distinct version
@@ -108,26 +319,34 @@ def _compress(in_stream: BytesIO, out_stream: BytesIO):
             # logger.debug(f"updated deque: {[v for v in prev_versions]}")
             out_stream.write(next_header[0:4])
 
-        # Prev Block Hash always omitted
-        bitfield = bitfield ^ MASK_PREV_BLOCK_HASH
+        # Prev Block Hash omitted unless asked to send it, or it does not link to
+        # prev_header and so cannot be reconstructed from it
+        if (
+            disable_prev_hash_omission
+            or full
+            or next_header[4:36] != hash_header(prev_header)
+        ):
+            out_stream.write(next_header[4:36])
+        else:
+            bitfield = bitfield ^ MASK_PREV_BLOCK_HASH
 
         # Merkle_root
         out_stream.write(next_header[36:68])
 
         # Time
-        (prev_time,) = struct.unpack("I", prev_header[68:72])
-        (next_time,) = struct.unpack("I", next_header[68:72])
-        time_offset = next_time - prev_time
+        offset = time_offset(prev_header[68:72], next_header[68:72])
         # If we can fit it as a 2 byte offset, do that
-        if MIN_INT <= time_offset <= MAX_INT:
+        if offset is not None and not (disable_time_offset or full):
             bitfield = bitfield ^ MASK_TIME
-            out_stream.write(struct.pack("<h", time_offset))
+            out_stream.write(offset)
         # Else copy the full 4 bytes
         else:
             out_stream.write(next_header[68:72])
 
         # nBits
-        if prev_header[72:76] == next_header[72:76]:
+        if prev_header[72:76] == next_header[72:76] and not (
+            disable_nbits_omission or full
+        ):
             # If the same, only set the bitfield
             bitfield = bitfield ^ MASK_NBITS
         else:
@@ -145,16 +364,56 @@ def _compress(in_stream: BytesIO, out_stream: BytesIO):
         # Seek to the end ready for the next header to be appended
         out_stream.seek(out_pos_end)
 
-
-def compress_headers(in_stream: BytesIO, out_stream: BytesIO) -> bool:
+        # A receiver may decode a self contained first record without prev_header, so
+        # later records must not refer to its version
+        if first_record and self_contained(bitfield):
+            prev_versions.pop()
+
+
+def compress_headers(
+    in_stream: BytesIO,
+    out_stream: BytesIO,
+    *,
+    sanity: bool = False,
+    allow_partial: bool = False,
+    disable_version_cache: bool = False,
+    disable_prev_hash_omission: bool = False,
+    disable_time_offset: bool = False,
+    disable_nbits_omission: bool = False,
+    first_full: bool = False,
+) -> bool:
     """
     Compress takes a stream of headers of length (start ... end)
     It compresses and returns (start + 1 ... end) into a return stream
 
+    If sanity is set, headers with a zero version, zero time or zero nBits mantissa
+    are refused rather than encoded.
+
+    Input whose length is not a multiple of 80 bytes is refused, unless allow_partial
+    is set, in which case the trailing partial header is dropped.
+
+    The disable_* flags force the explicit encoding of a single field, to measure
+    what each optimisation saves. The output stays decodable by any decompressor.
+
+    If first_full is set, the first record carries an explicit version, prev_block_hash,
+    time and nBits, as the spec requires of the first header sent to a new peer. Any
+    self contained first record drops the version of the header before it from the
+    cache, so the output decodes for a receiver which does not hold that header.
+
     :return bool indicating success
     """
     try:
-        _compress(in_stream, out_stream)
+        _compress(
+            in_stream,
+            out_stream,
+            sanity,
+            allow_partial,
+            disable_version_cache,
+            disable_prev_hash_omission,
+            disable_time_offset,
+            disable_nbits_omission,
+            first_full,
+        )
     # Likely an error from stream reading or writing
     except OSError as e:
         logger.exception(e)
@@ -163,88 +422,180 @@ def compress_headers(in_stream: BytesIO, out_stream: BytesIO) -> bool:
     except struct.error as e:
         logger.exception(e)
         return False
+    # A header failed validation
+    except CompressionError as e:
+        logger.exception(e)
+        return False
 
     return True
 
 
-def _decompress(in_stream: BytesIO, out_stream: BytesIO, prev_header: bytes):
-    first = True
-    end = False
+def _decompress(
+    in_stream: BytesIO,
+    out_stream: BytesIO,
+    prev_header: bytes,
+    sanity: bool,
+    tip_hash,
+    strict: bool,
+    max_headers,
+    max_bytes,
+    version_cache_len: int = VERSION_CACHE_LEN,
+):
+    # Check the expected tip before decoding anything
+    expected_tip = _internal_hash(tip_hash) if tip_hash is not None else None
+    header_index = 0
+    in_pos_begin = in_stream.tell()
     # Init the previous version deque
-    prev_versions = deque(maxlen=7)
+    prev_versions = deque(maxlen=version_cache_len)
     # Add prev_header to the dequeue
     prev_versions.appendleft(prev_header[0:4])
 
-    while not end:
-        # On the first iteration only prev_header is taken from function parameter
-        if first:
-            prev_header = BytesIO(prev_header)
-            first = False
-        # Else we rewind the out stream and extract it from there
-        else:
-            out_pos_start = out_stream.tell()
-            out_stream.seek(out_pos_start - 80)
-            prev_header = BytesIO(out_stream.read(HEADER_LEN))
-
+    while True:
         # Bitfield
-        bitfield = int.from_bytes(in_stream.read(1), "little")
+        record_start = in_stream.tell()
+        bitfield_byte = in_stream.read(1)
+        # A stream may also end cleanly on a record boundary without sequence_end
+        if not bitfield_byte:
+            break
+        bitfield = int.from_bytes(bitfield_byte, "little")
+        if max_headers is not None and header_index >= max_headers:
+            raise LimitExceededError("headers", max_headers)
+
+        # The bitfield tells us the record length, so read the remainder in one go
+        record = _read_exact(in_stream, record_length(bitfield), record_start)
+        pos = 0
+        # Reserved bits carry nothing, so a set one can only be a covert channel
+        if strict and bitfield & ~MASK_DEFINED:
+            raise NonCanonicalError(header_index, "bitfield")
+        # A self contained first record is the spec's full first header
+        full = header_index == 0 and self_contained(bitfield)
+        canonical_checks = strict and not full
 
         # Version
         v_index = bitfield >> 5
         if v_index == NEW_DISTINCT_VERSION:
             # Version not in previous 7 distinct versions
-            version = in_stream.read(4)
+            version = record[pos : pos + 4]
+            pos += 4
+            if canonical_checks and version in prev_versions:
+                raise NonCanonicalError(header_index, "version")
             prev_versions.appendleft(version)
-            out_stream.write(version)
+            # The sender may not have assumed we hold prev_header, so forget its version
+            if full:
+                prev_versions.pop()
+        elif v_index < len(prev_versions):
+            version = prev_versions[v_index]
         else:
-            out_stream.write(prev_versions[v_index])
+            raise VersionIndexError(header_index, v_index, len(prev_versions))
 
         # Prev_block_hash
-        out_stream.write(hash_header(prev_header.read(HEADER_LEN)))
-        prev_header.seek(0)
+        if bitfield & MASK_PREV_BLOCK_HASH:
+            prev_block_hash = hash_header(prev_header)
+        else:
+            prev_block_hash = record[pos : pos + 32]
+            pos += 32
+            if canonical_checks and prev_block_hash == hash_header(prev_header):
+                raise NonCanonicalError(header_index, "prev_block_hash")
 
         # Merkle_root
-        out_stream.write(in_stream.read(32))
+        merkle_root = record[pos : pos + 32]
+        pos += 32
 
         # Time
         if bitfield & MASK_TIME:
-            (time_offset,) = struct.unpack("<h", in_stream.read(2))
-            prev_header.seek(68)
-            (time_prev,) = struct.unpack("I", prev_header.read(4))
-            prev_header.seek(0)
-            out_stream.write(struct.pack("I", (time_prev + time_offset)))
+            time = apply_time_offset(prev_header[68:72], record[pos : pos + 2])
+            pos += 2
         else:
-            out_stream.write(in_stream.read(4))
+            time = record[pos : pos + 4]
+            pos += 4
+            if canonical_checks and time_offset(prev_header[68:72], time) is not None:
+                raise NonCanonicalError(header_index, "time")
 
         # nBits
         if bitfield & MASK_NBITS:
-            prev_header.seek(72)
-            out_stream.write(prev_header.read(4))
-            prev_header.seek(0)
+            n_bits = prev_header[72:76]
         else:
-            out_stream.write(in_stream.read(4))
+            n_bits = record[pos : pos + 4]
+            pos += 4
+            if canonical_checks and n_bits == prev_header[72:76]:
+                raise NonCanonicalError(header_index, "nBits")
 
         # Nonce
-        out_stream.write(in_stream.read(4))
+        nonce = record[pos : pos + 4]
+
+        header = version + prev_block_hash + merkle_root + time + n_bits + nonce
+
+        # Refuse the header which took us over the byte limit, keeping those before it
+        if max_bytes is not None and in_stream.tell() - in_pos_begin > max_bytes:
+            raise LimitExceededError("bytes", max_bytes)
+
+        if sanity:
+            check_sanity(header)
+
+        out_stream.write(header)
+        prev_header = header
+        header_index += 1
 
         # Check if this is final header
         if bitfield & MASK_END:
-            end = True
+            break
+
+    if expected_tip is not None:
+        # If nothing was decoded the tip is the header we were given
+        actual = hash_header(prev_header)
+        if actual != expected_tip:
+            raise TipMismatchError(expected_tip, actual)
 
 
 def decompress_headers(
-    in_stream: BytesIO, out_stream: BytesIO, prev_header: bytes
+    in_stream: BytesIO,
+    out_stream: BytesIO,
+    prev_header: bytes,
+    *,
+    sanity: bool = False,
+    tip_hash: Optional[Union[str, bytes]] = None,
+    strict: bool = False,
+    max_headers: Optional[int] = None,
+    max_bytes: Optional[int] = None,
 ) -> bool:
     """
     decompress takes a stream of compressed header(s) of length (start ... end) and a
     previous_header bytes object.
     It decompresses all compressed headers and inserts them into the return stream,
     excluding the previous_header.
+    Decompression ends at the header with sequence_end set, or when the input stream
+    ends on a record boundary. A stream ending part way through a record is refused.
+
+    If sanity is set, decoding stops at the first header with a zero version, zero
+    time or zero nBits mantissa.
+
+    If tip_hash is given, the hash of the final decompressed header must match it. It
+    may be display hex (str) or bytes in internal byte order, as from hash_header().
+
+    If strict is set, records with a reserved bitfield bit set or which transmit a
+    field the compressor should have omitted are refused: an explicit version already
+    in the cache, an explicit prev_block_hash of the previous header, a full time whose
+    offset fits in 2 bytes, or explicit nBits equal to the previous header's. A first
+    record with all four fields explicit is accepted, as compress_headers() emits with
+    first_full, and prev_header's version is then dropped from the cache.
+
+    If max_headers or max_bytes are given, decoding stops with an error once the input
+    holds more headers or compressed bytes than allowed, even if it would continue.
+    Every header decoded within the limits is kept in the output stream.
 
     :return bool indicating success
     """
     try:
-        _decompress(in_stream, out_stream, prev_header)
+        _decompress(
+            in_stream,
+            out_stream,
+            prev_header,
+            sanity,
+            tip_hash,
+            strict,
+            max_headers,
+            max_bytes,
+        )
     # Likely an error from stream reading or writing
     except OSError as e:
         logger.exception(e)
@@ -253,5 +604,9 @@ def decompress_headers(
     except struct.error as e:
         logger.exception(e)
         return False
+    # A header failed validation
+    except CompressionError as e:
+        logger.exception(e)
+        return False
 
     return True
@@ -0,0 +1,74 @@
+"""
+Genesis block headers for the supported networks.
+
+Headers are raw 80 byte serializations. Hashes are in internal byte order, as returned
+by hash_header(); reverse them for the usual display hex.
+"""
+
+
+from enum import Enum
+
+
+class Network(Enum):
+    MAINNET = 1
+    TESTNET = 2
+    SIGNET = 3
+    REGTEST = 4
+
+
+GENESIS_MAINNET = bytes.fromhex(
+    "01000000"
+    "0000000000000000000000000000000000000000000000000000000000000000"
+    "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a"
+    "29ab5f49"
+    "ffff001d"
+    "1dac2b7c"
+)
+GENESIS_TESTNET = bytes.fromhex(
+    "01000000"
+    "0000000000000000000000000000000000000000000000000000000000000000"
+    "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a"
+    "dae5494d"
+    "ffff001d"
+    "1aa4ae18"
+)
+GENESIS_SIGNET = bytes.fromhex(
+    "01000000"
+    "0000000000000000000000000000000000000000000000000000000000000000"
+    "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a"
+    "008f4d5f"
+    "ae77031e"
+    "8ad22203"
+)
+GENESIS_REGTEST = bytes.fromhex(
+    "01000000"
+    "0000000000000000000000000000000000000000000000000000000000000000"
+    "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a"
+    "dae5494d"
+    "ffff7f20"
+    "02000000"
+)
+
+GENESIS_HASH_MAINNET = bytes.fromhex(
+    "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+)[::-1]
+GENESIS_HASH_TESTNET = bytes.fromhex(
+    "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"
+)[::-1]
+GENESIS_HASH_SIGNET = bytes.fromhex(
+    "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"
+)[::-1]
+GENESIS_HASH_REGTEST = bytes.fromhex(
+    "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"
+)[::-1]
+
+_GENESIS = {
+    Network.MAINNET: GENESIS_MAINNET,
+    Network.TESTNET: GENESIS_TESTNET,
+    Network.SIGNET: GENESIS_SIGNET,
+    Network.REGTEST: GENESIS_REGTEST,
+}
+
+
+def genesis_header(network: Network) -> bytes:
+    return _GENESIS[network]
@@ -1,15 +1,23 @@
 import argparse
 import logging
+import re
+import struct
 import sys
 from enum import Enum
 from io import BytesIO
 from pathlib import Path
-from random import randint
+from random import Random, randint
 from time import perf_counter
 
 import requests
+from header_codec import codec
 from header_codec.codec import compress_headers, CompressionError, decompress_headers, \
-    hash_header, HEADER_LEN
+    LimitExceededError, NonCanonicalError, TrailingBytesError, \
+    hash_header, HEADER_LEN, MASK_END, MASK_NBITS, MASK_PREV_BLOCK_HASH, MASK_TIME, \
+    NEW_DISTINCT_VERSION, VERSION_CACHE_LEN, SPEC_MIN_RECORD_LEN, SPEC_MAX_RECORD_LEN, \
+    FULL_RECORD_LEN, MIN_INT, MAX_INT, record_length, time_offset, apply_time_offset
+from header_codec.constants import Network, genesis_header, GENESIS_MAINNET, \
+    GENESIS_HASH_MAINNET, GENESIS_HASH_TESTNET, GENESIS_HASH_SIGNET, GENESIS_HASH_REGTEST
 
 logging.basicConfig(level=logging.DEBUG)
 logger = logging.getLogger("test_codec")
@@ -17,7 +25,34 @@ logger.setLevel(logging.DEBUG)
 logging.getLogger("urllib3.connectionpool").setLevel(logging.WARNING)
 
 REST_URL = "http://127.0.0.1:8332"
-GENESIS_HEADER = b"\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00;\xa3\xed\xfdz{\x12\xb2z\xc7,>gv\x8fa\x7f\xc8\x1b\xc3\x88\x8aQ2:\x9f\xb8\xaaK\x1e^J)\xab_I\xff\xff\x00\x1d\x1d\xac+|"
+# Whole chain saving claimed by the spec, and how far a full run may stray from it
+SPEC_SAVING = 0.49
+SPEC_SAVING_TOLERANCE = 0.04
+# Saving on synthetic_chain(1000), pinned tightly enough that losing any optimisation fails
+SYNTHETIC_SAVING = 0.5037
+SYNTHETIC_SAVING_TOLERANCE = 0.005
+GENESIS_HEADER = GENESIS_MAINNET
+# Mainnet blocks 1 and 2, used as an offline fixture
+MAINNET_HEADERS = GENESIS_HEADER + bytes.fromhex(
+    "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe"
+    "680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299"
+    "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a5a"
+    "ddedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61"
+)
+MAINNET_TIP_HASH = "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd"
+# MAINNET_HEADERS compressed, checked against the spec by hand
+MAINNET_COMPRESSED = bytes.fromhex(
+    # Block 1: prev_block_hash omitted, nBits same, full time
+    "14"
+    "982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e"
+    "61bc6649"
+    "01e36299"
+    # Block 2: prev_block_hash omitted, time offset +79, nBits same, sequence_end
+    "1e"
+    "d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9b"
+    "4f00"
+    "08d2bd61"
+)
 
 
 class Source(Enum):
@@ -29,6 +64,31 @@ def header_hex(header: bytes):
     return bytes(reversed(hash_header(header))).hex()
 
 
+class ErrorCapture(logging.Handler):
+    def __init__(self):
+        super().__init__()
+        self.errors = []
+
+    def emit(self, record: logging.LogRecord):
+        if record.exc_info:
+            self.errors.append(record.exc_info[1])
+
+
+def codec_error(call, *args, **kwargs) -> Exception:
+    """
+    Run a codec call which is expected to fail, returning the error it logged.
+    """
+    capture = ErrorCapture()
+    codec_logger = logging.getLogger("header_codec")
+    codec_logger.addHandler(capture)
+    try:
+        assert not call(*args, **kwargs)
+    finally:
+        codec_logger.removeHandler(capture)
+    assert len(capture.errors) == 1, capture.errors
+    return capture.errors[0]
+
+
 def bitcoin_rest_request(request: str) -> bytes:
     response = requests.get(request)
     if not response.status_code == 200:
@@ -87,6 +147,746 @@ def get_headers(blockhash="", count=2000, height=0, to_tip=True) -> BytesIO:
         return headers
 
 
+def child_header(parent: bytes, **fields) -> bytes:
+    """
+    Build a header extending parent, copying any field not given from the parent.
+    """
+    version = fields.get("version", parent[0:4])
+    merkle_root = fields.get("merkle_root", parent[36:68])
+    time = fields.get("time", parent[68:72])
+    n_bits = fields.get("n_bits", parent[72:76])
+    nonce = fields.get("nonce", parent[76:80])
+    return version + hash_header(parent) + merkle_root + time + n_bits + nonce
+
+
+def random_bytes(rng: Random, n: int) -> bytes:
+    # Random.randbytes() is only available from Python 3.9, and 3.8 rejects getrandbits(0)
+    if n == 0:
+        return b""
+    return rng.getrandbits(8 * n).to_bytes(n, "little")
+
+
+def synthetic_chain(count: int, seed: int = 0) -> bytes:
+    """
+    Build a chain of count headers after genesis, with a handful of recurring versions,
+    occasional large or negative time jumps and occasional nBits changes.
+    """
+    rng = Random(seed)
+    versions = [1, 2, 0x20000000, 0x20400000, 0x3FFF0000, 0x20000004, 0x27FFE000, 0x20C00000]
+    headers = [GENESIS_HEADER]
+    (time,) = struct.unpack("<I", GENESIS_HEADER[68:72])
+    n_bits = GENESIS_HEADER[72:76]
+    for _ in range(count):
+        if rng.random() < 0.05:
+            time += rng.choice([-70000, -40000, 40000, 100000])
+        else:
+            time += rng.randint(-100, 1200)
+        if rng.random() < 0.01:
+            n_bits = random_bytes(rng, 4)
+        headers.append(
+            child_header(
+                headers[-1],
+                version=struct.pack("<i", rng.choice(versions)),
+                merkle_root=random_bytes(rng, 32),
+                time=struct.pack("<I", time),
+                n_bits=n_bits,
+                nonce=random_bytes(rng, 4),
+            )
+        )
+    return b"".join(headers)
+
+
+def test_genesis_hashes():
+    """
+    Hash the genesis header of each network.
+    Asserting the results match both the canonical display hashes and the constants.
+    """
+    logger.info(f"starting test_genesis_hashes")
+
+    expected = {
+        Network.MAINNET: ("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f", GENESIS_HASH_MAINNET),
+        Network.TESTNET: ("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943", GENESIS_HASH_TESTNET),
+        Network.SIGNET: ("00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6", GENESIS_HASH_SIGNET),
+        Network.REGTEST: ("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206", GENESIS_HASH_REGTEST),
+    }
+    for network, (display_hash, internal_hash) in expected.items():
+        header = genesis_header(network)
+        assert len(header) == HEADER_LEN, network
+        assert header_hex(header) == display_hash, network
+        assert hash_header(header) == internal_hash, network
+
+
+def test_sanity():
+    """
+    Run compression and decompression over headers with nonsensical fields.
+    Asserting they round-trip by default and are refused when sanity checking is on.
+    """
+    logger.info(f"starting test_sanity")
+
+    insane_fields = [
+        ("version", bytes(4)),
+        ("time", bytes(4)),
+        ("n_bits", bytes(4)),
+        # Non-zero exponent but zero mantissa
+        ("n_bits", bytes.fromhex("0000001d")),
+    ]
+    for field, value in insane_fields:
+        name = f"{field}={value.hex()}"
+        insane = child_header(GENESIS_HEADER, **{field: value})
+
+        cout = BytesIO()
+        assert compress_headers(BytesIO(GENESIS_HEADER + insane), cout), name
+        cout.seek(0)
+        dout = BytesIO()
+        assert decompress_headers(cout, dout, GENESIS_HEADER), name
+        assert dout.getvalue() == insane, name
+
+        assert not compress_headers(BytesIO(GENESIS_HEADER + insane), BytesIO(), sanity=True), name
+        cout.seek(0)
+        assert not decompress_headers(cout, BytesIO(), GENESIS_HEADER, sanity=True), name
+
+    # A sane chain is unaffected by the check
+    sane = child_header(GENESIS_HEADER, nonce=bytes(4))
+    cout = BytesIO()
+    assert compress_headers(BytesIO(GENESIS_HEADER + sane), cout, sanity=True)
+    cout.seek(0)
+    dout = BytesIO()
+    assert decompress_headers(cout, dout, GENESIS_HEADER, sanity=True)
+    assert dout.getvalue() == sane
+
+
+def test_tip_hash():
+    """
+    Run decompression of a short chain against an expected tip hash.
+    Asserting a matching tip is accepted in either byte order and a mismatch is refused.
+    """
+    logger.info(f"starting test_tip_hash")
+
+    header_1 = child_header(GENESIS_HEADER, nonce=bytes(4))
+    header_2 = child_header(header_1, nonce=bytes(4))
+    cout = BytesIO()
+    assert compress_headers(BytesIO(GENESIS_HEADER + header_1 + header_2), cout)
+
+    for tip_hash in (hash_header(header_2), header_hex(header_2)):
+        cout.seek(0)
+        dout = BytesIO()
+        assert decompress_headers(cout, dout, GENESIS_HEADER, tip_hash=tip_hash)
+        assert dout.getvalue() == header_1 + header_2
+
+    for tip_hash in (hash_header(header_1), header_hex(header_1), GENESIS_HASH_MAINNET):
+        cout.seek(0)
+        assert not decompress_headers(cout, BytesIO(), GENESIS_HEADER, tip_hash=tip_hash)
+
+    # Malformed tips are refused before decoding, never raised
+    malformed = [
+        "zz",
+        header_hex(header_2)[:-2],
+        header_hex(header_2) + "00",
+        hash_header(header_2)[:-1],
+        5,
+        bytes(5),
+    ]
+    for tip_hash in malformed:
+        cout.seek(0)
+        dout = BytesIO()
+        assert not decompress_headers(cout, dout, GENESIS_HEADER, tip_hash=tip_hash), tip_hash
+        assert dout.getvalue() == b"", tip_hash
+
+
+def test_partial_input():
+    """
+    Run compression over input with a trailing partial header and decompression over
+    truncated compressed input.
+    Asserting partial input is refused unless allowed, and that a compressed stream
+    may only end on a record boundary.
+    """
+    logger.info(f"starting test_partial_input")
+
+    header_1 = child_header(GENESIS_HEADER, nonce=bytes(4))
+    header_2 = child_header(header_1, nonce=bytes(4))
+    headers = GENESIS_HEADER + header_1 + header_2
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout)
+
+    # 79 trailing bytes on the raw input
+    error = codec_error(compress_headers, BytesIO(headers + header_2[:79]), BytesIO())
+    assert isinstance(error, TrailingBytesError)
+    assert (error.count, error.offset) == (79, 3 * HEADER_LEN)
+    partial_out = BytesIO()
+    assert compress_headers(BytesIO(headers + header_2[:79]), partial_out, allow_partial=True)
+    assert partial_out.getvalue() == cout.getvalue()
+    # Less than a single header
+    error = codec_error(compress_headers, BytesIO(GENESIS_HEADER[:79]), BytesIO())
+    assert isinstance(error, TrailingBytesError)
+    assert (error.count, error.offset) == (79, 0)
+    assert compress_headers(BytesIO(GENESIS_HEADER[:79]), BytesIO(), allow_partial=True)
+
+    first_record = BytesIO()
+    assert compress_headers(BytesIO(GENESIS_HEADER + header_1), first_record)
+    record_len = first_record.tell()
+
+    # Compressed stream truncated one byte before the end, part way into the second record
+    truncated = BytesIO(cout.getvalue()[:-1])
+    error = codec_error(decompress_headers, truncated, BytesIO(), GENESIS_HEADER)
+    assert isinstance(error, TrailingBytesError)
+    assert (error.count, error.offset) == (len(cout.getvalue()) - 1 - record_len, record_len)
+
+    # Compressed stream ending on a record boundary without sequence_end
+    unterminated = BytesIO(cout.getvalue()[:record_len])
+    dout = BytesIO()
+    assert decompress_headers(unterminated, dout, GENESIS_HEADER)
+    assert dout.getvalue() == header_1
+
+
+def test_golden_vectors():
+    """
+    Run compression and decompression over the embedded mainnet fixture.
+    Asserting the compressed bytes match the golden vector exactly, so any platform or
+    refactor dependent change to the wire format fails loudly.
+    """
+    logger.info(f"starting test_golden_vectors")
+
+    cout = BytesIO()
+    assert compress_headers(BytesIO(MAINNET_HEADERS), cout)
+    assert cout.getvalue() == MAINNET_COMPRESSED, cout.getvalue().hex()
+
+    dout = BytesIO()
+    assert decompress_headers(
+        BytesIO(MAINNET_COMPRESSED), dout, GENESIS_HEADER, tip_hash=MAINNET_TIP_HASH
+    )
+    assert dout.getvalue() == MAINNET_HEADERS[HEADER_LEN:]
+
+
+def test_explicit_byte_order():
+    """
+    Check every struct format string in the codec has an explicit little endian prefix,
+    as native byte order would make the wire format depend on the host.
+    """
+    logger.info(f"starting test_explicit_byte_order")
+
+    source = Path(__file__).parent.parent.joinpath("header_codec", "codec.py").read_text()
+    formats = re.findall(r"struct\.(?:un)?pack\(\s*\"([^\"]*)\"", source)
+    assert formats
+    for fmt in formats:
+        assert fmt.startswith("<"), fmt
+
+
+def test_strict_canonical():
+    """
+    Run strict decompression over handcrafted non-canonical records and a synthetic
+    chain compressed by the codec.
+    Asserting each non-canonical field is refused only in strict mode with an error naming
+    it, and that our own compressor output never is.
+    """
+    logger.info(f"starting test_strict_canonical")
+
+    header = child_header(GENESIS_HEADER, nonce=bytes(4))
+    version, merkle_root, time, n_bits, nonce = (
+        header[0:4], header[36:68], header[68:72], header[72:76], header[76:80]
+    )
+    canonical_bitfield = MASK_PREV_BLOCK_HASH | MASK_TIME | MASK_NBITS | MASK_END
+    non_canonical = {
+        # Explicit version which is already cached at index 0
+        "version": (canonical_bitfield | NEW_DISTINCT_VERSION << 5).to_bytes(1, "little")
+        + version + merkle_root + bytes(2) + nonce,
+        # Full time with an offset of zero
+        "time": (canonical_bitfield ^ MASK_TIME).to_bytes(1, "little")
+        + merkle_root + time + nonce,
+        # Explicit nBits equal to the previous header's
+        "nBits": (canonical_bitfield ^ MASK_NBITS).to_bytes(1, "little")
+        + merkle_root + bytes(2) + n_bits + nonce,
+        # The reserved bit set on an otherwise canonical record
+        "bitfield": (canonical_bitfield | 1).to_bytes(1, "little")
+        + merkle_root + bytes(2) + nonce,
+    }
+    for field, record in non_canonical.items():
+        dout = BytesIO()
+        assert decompress_headers(BytesIO(record), dout, GENESIS_HEADER), field
+        assert dout.getvalue() == header, field
+        error = codec_error(
+            decompress_headers, BytesIO(record), BytesIO(), GENESIS_HEADER, strict=True
+        )
+        assert isinstance(error, NonCanonicalError), field
+        assert (error.header_index, error.field) == (0, field)
+
+    headers = synthetic_chain(5000)
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout)
+    cout.seek(0)
+    dout = BytesIO()
+    assert decompress_headers(cout, dout, GENESIS_HEADER, strict=True)
+    assert dout.getvalue() == headers[HEADER_LEN:]
+
+
+def test_no_raise():
+    """
+    Run compression and decompression over random bytes.
+    Asserting both always report failure by returning False rather than raising.
+    """
+    logger.info(f"starting test_no_raise")
+
+    rng = Random(0)
+    for _ in range(2000):
+        data = random_bytes(rng, rng.randint(0, 4 * HEADER_LEN))
+        assert compress_headers(BytesIO(data), BytesIO()) in (True, False)
+        assert decompress_headers(BytesIO(data), BytesIO(), GENESIS_HEADER) in (True, False)
+
+    # A version index past the end of the cache
+    record = bytes([1 << 5 | MASK_PREV_BLOCK_HASH | MASK_END]) + bytes(42)
+    assert not decompress_headers(BytesIO(record), BytesIO(), GENESIS_HEADER)
+
+
+def test_limits():
+    """
+    Run decompression of a synthetic chain past header and byte limits.
+    Asserting it stops with an error naming the limit, keeping every header decoded
+    within the limits.
+    """
+    logger.info(f"starting test_limits")
+
+    headers = synthetic_chain(10)
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout)
+    compressed = cout.getvalue()
+
+    # Exactly at the limits is fine
+    assert decompress_headers(BytesIO(compressed), BytesIO(), GENESIS_HEADER, max_headers=10)
+    assert decompress_headers(
+        BytesIO(compressed), BytesIO(), GENESIS_HEADER, max_bytes=len(compressed)
+    )
+
+    dout = BytesIO()
+    error = codec_error(
+        decompress_headers, BytesIO(compressed), dout, GENESIS_HEADER, max_headers=4
+    )
+    assert isinstance(error, LimitExceededError)
+    assert (error.limit, error.maximum) == ("headers", 4)
+    assert dout.getvalue() == headers[HEADER_LEN:5 * HEADER_LEN]
+
+    # Find where the fourth record ends so the byte limit falls part way into the fifth
+    four_records = BytesIO()
+    assert compress_headers(BytesIO(headers[:5 * HEADER_LEN]), four_records)
+    dout = BytesIO()
+    max_bytes = four_records.tell() + 1
+    error = codec_error(
+        decompress_headers, BytesIO(compressed), dout, GENESIS_HEADER, max_bytes=max_bytes
+    )
+    assert isinstance(error, LimitExceededError)
+    assert (error.limit, error.maximum) == ("bytes", max_bytes)
+    assert dout.getvalue() == headers[HEADER_LEN:5 * HEADER_LEN]
+
+
+def test_version_sentinel():
+    """
+    Run compression and decompression over chains cycling through more distinct versions
+    than the cache holds.
+    Asserting the compressor never emits the sentinel as a cache index, refuses to when
+    the cache is made too long, and strict decompression refuses an explicit version
+    still in the cache.
+    """
+    logger.info(f"starting test_version_sentinel")
+
+    assert VERSION_CACHE_LEN == NEW_DISTINCT_VERSION
+
+    headers = [GENESIS_HEADER]
+    for i in range(3 * (VERSION_CACHE_LEN + 1)):
+        version = struct.pack("<i", 2 + i % (VERSION_CACHE_LEN + 1))
+        headers.append(child_header(headers[-1], version=version))
+    cout = BytesIO()
+    assert compress_headers(BytesIO(b"".join(headers)), cout)
+    # Cycling through one more version than the cache holds always misses
+    record_len = 1 + 4 + 32 + 2 + 4
+    compressed = cout.getvalue()
+    assert len(compressed) == (len(headers) - 1) * record_len
+    for bitfield in compressed[::record_len]:
+        assert bitfield >> 5 == NEW_DISTINCT_VERSION
+    cout.seek(0)
+    dout = BytesIO()
+    assert decompress_headers(cout, dout, GENESIS_HEADER, strict=True)
+    assert dout.getvalue() == b"".join(headers[1:])
+
+    # Fill the cache so genesis' version sits at the last index, then send it explicitly
+    headers = [GENESIS_HEADER]
+    for i in range(VERSION_CACHE_LEN - 1):
+        headers.append(child_header(headers[-1], version=struct.pack("<i", 2 + i)))
+    headers.append(child_header(headers[-1], version=GENESIS_HEADER[0:4]))
+    cout = BytesIO()
+    assert compress_headers(BytesIO(b"".join(headers)), cout)
+    compressed = cout.getvalue()
+    last_record = len(compressed) - (1 + 32 + 2 + 4)
+    assert compressed[last_record] >> 5 == VERSION_CACHE_LEN - 1
+    explicit = (
+        compressed[:last_record]
+        + bytes([compressed[last_record] | NEW_DISTINCT_VERSION << 5])
+        + GENESIS_HEADER[0:4]
+        + compressed[last_record + 1:]
+    )
+    dout = BytesIO()
+    assert decompress_headers(BytesIO(explicit), dout, GENESIS_HEADER)
+    assert dout.getvalue() == b"".join(headers[1:])
+    error = codec_error(
+        decompress_headers, BytesIO(explicit), BytesIO(), GENESIS_HEADER, strict=True
+    )
+    assert isinstance(error, NonCanonicalError)
+    assert (error.header_index, error.field) == (len(headers) - 2, "version")
+
+    # A cache longer than the sentinel allows would hand out index 7 on a hit, which the
+    # compressor must refuse rather than emit as a new version
+    headers = [GENESIS_HEADER]
+    for i in range(VERSION_CACHE_LEN):
+        headers.append(child_header(headers[-1], version=struct.pack("<i", 2 + i)))
+    headers.append(child_header(headers[-1], version=GENESIS_HEADER[0:4]))
+    def compress(version_cache_len: int):
+        codec._compress(
+            BytesIO(b"".join(headers)),
+            BytesIO(),
+            sanity=False,
+            allow_partial=False,
+            disable_version_cache=False,
+            disable_prev_hash_omission=False,
+            disable_time_offset=False,
+            disable_nbits_omission=False,
+            first_full=False,
+            version_cache_len=version_cache_len,
+        )
+
+    try:
+        compress(NEW_DISTINCT_VERSION + 1)
+    except CompressionError as e:
+        assert "sentinel" in str(e), e
+    else:
+        assert False, "cache index 7 was emitted"
+    compress(VERSION_CACHE_LEN)
+    assert compress_headers(BytesIO(b"".join(headers)), BytesIO())
+
+
+class CountingReader(BytesIO):
+    def __init__(self, data: bytes):
+        super().__init__(data)
+        self.reads = 0
+
+    def read(self, size=-1):
+        self.reads += 1
+        return super().read(size)
+
+
+def test_record_reads():
+    """
+    Run decompression of a synthetic chain through a reader counting read calls.
+    Asserting each record costs exactly two reads: the bitfield, then the remainder.
+    """
+    logger.info(f"starting test_record_reads")
+
+    headers = synthetic_chain(500)
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout)
+
+    cin = CountingReader(cout.getvalue())
+    dout = BytesIO()
+    assert decompress_headers(cin, dout, GENESIS_HEADER)
+    assert dout.getvalue() == headers[HEADER_LEN:]
+    assert cin.reads == 2 * 500
+
+
+def test_record_lengths():
+    """
+    Check the record length of every bitfield omitting prev_block_hash lies within the
+    range given by the spec, reaching both ends of it, and that no record is longer than
+    one with every field explicit.
+    """
+    logger.info(f"starting test_record_lengths")
+
+    lengths = [
+        1 + record_length(bitfield)
+        for bitfield in range(256)
+        if bitfield & MASK_PREV_BLOCK_HASH
+    ]
+    assert min(lengths) == SPEC_MIN_RECORD_LEN == 39
+    assert max(lengths) == SPEC_MAX_RECORD_LEN == 49
+    assert max(1 + record_length(bitfield) for bitfield in range(256)) == FULL_RECORD_LEN
+
+    # The fixture holds one record of each kind
+    assert len(MAINNET_COMPRESSED) == 1 + record_length(MAINNET_COMPRESSED[0]) + SPEC_MIN_RECORD_LEN
+
+
+DISABLE_FLAGS = [
+    "disable_version_cache",
+    "disable_prev_hash_omission",
+    "disable_time_offset",
+    "disable_nbits_omission",
+]
+
+
+def test_disabled_optimisations():
+    """
+    Run compression of a synthetic chain with each optimisation disabled, then all of them.
+    Asserting every output decodes, and that with everything disabled each record holds
+    every field explicitly.
+    """
+    logger.info(f"starting test_disabled_optimisations")
+
+    num_headers = 1000
+    headers = synthetic_chain(num_headers)
+    plain = BytesIO()
+    assert compress_headers(BytesIO(headers), plain)
+
+    configs = [{flag: True} for flag in DISABLE_FLAGS] + [dict.fromkeys(DISABLE_FLAGS, True)]
+    for config in configs:
+        cout = BytesIO()
+        assert compress_headers(BytesIO(headers), cout, **config), config
+        assert cout.tell() > plain.tell(), config
+        cout.seek(0)
+        dout = BytesIO()
+        assert decompress_headers(cout, dout, GENESIS_HEADER), config
+        assert dout.getvalue() == headers[HEADER_LEN:], config
+
+    assert cout.tell() == num_headers * FULL_RECORD_LEN
+
+    # Only prev_block_hash disabled costs exactly 32 bytes per header
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout, disable_prev_hash_omission=True)
+    assert cout.tell() == plain.tell() + num_headers * 32
+    # and is exactly what strict mode calls non-canonical
+    cout.seek(0)
+    error = codec_error(decompress_headers, cout, BytesIO(), GENESIS_HEADER, strict=True)
+    assert isinstance(error, NonCanonicalError)
+    assert (error.header_index, error.field) == (0, "prev_block_hash")
+
+
+def test_spec_saving():
+    """
+    Run compression of a synthetic chain, then again with each optimisation disabled.
+    Asserting the saving matches its pinned value within the spec's claim, and moves
+    away from it when any optimisation is lost.
+    """
+    logger.info(f"starting test_spec_saving")
+
+    num_headers = 1000
+    headers = synthetic_chain(num_headers)
+
+    def saving(**config) -> float:
+        cout = BytesIO()
+        assert compress_headers(BytesIO(headers), cout, **config), config
+        return 1 - cout.tell() / (num_headers * HEADER_LEN)
+
+    measured = saving()
+    assert abs(measured - SYNTHETIC_SAVING) <= SYNTHETIC_SAVING_TOLERANCE, (
+        f"compression saved {measured:.2%} of the synthetic chain, "
+        f"expected {SYNTHETIC_SAVING:.2%}"
+    )
+    assert abs(measured - SPEC_SAVING) <= SPEC_SAVING_TOLERANCE
+    for flag in DISABLE_FLAGS:
+        assert abs(saving(**{flag: True}) - SYNTHETIC_SAVING) > SYNTHETIC_SAVING_TOLERANCE, flag
+
+
+def field_contributions(headers: BytesIO) -> dict:
+    """
+    Compress headers once normally and once with each optimisation disabled.
+    Returns how many extra bytes disabling each optimisation costs.
+    """
+    headers.seek(0)
+    plain = BytesIO()
+    compress_headers(headers, plain)
+    contributions = {}
+    for flag in DISABLE_FLAGS:
+        headers.seek(0)
+        cout = BytesIO()
+        compress_headers(headers, cout, **{flag: True})
+        contributions[flag] = cout.tell() - plain.tell()
+    return contributions
+
+
+def test_negative_versions():
+    """
+    Run compression and decompression over a chain of negative versions, mixing cache
+    misses and hits, with default, strict and fully explicit encodings.
+    Asserting every configuration round-trips byte for byte.
+    """
+    logger.info(f"starting test_negative_versions")
+
+    versions = [-1, -(2 ** 31), -0x20000000, 1, -1, -(2 ** 31), 2 ** 31 - 1, -0x20000000]
+    headers = [GENESIS_HEADER]
+    for version in versions:
+        headers.append(child_header(headers[-1], version=struct.pack("<i", version)))
+    headers = b"".join(headers)
+
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout, sanity=True)
+    # Genesis seeds the cache with version 1, and hits do not move to the front
+    expected_indices = [NEW_DISTINCT_VERSION] * 3 + [3, 2, 1, NEW_DISTINCT_VERSION, 1]
+    pos = 0
+    compressed = cout.getvalue()
+    for expected_index in expected_indices:
+        assert compressed[pos] >> 5 == expected_index
+        pos += 1 + record_length(compressed[pos])
+    assert pos == len(compressed)
+
+    cout.seek(0)
+    dout = BytesIO()
+    assert decompress_headers(cout, dout, GENESIS_HEADER, sanity=True, strict=True)
+    assert dout.getvalue() == headers[HEADER_LEN:]
+
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout, **dict.fromkeys(DISABLE_FLAGS, True))
+    cout.seek(0)
+    dout = BytesIO()
+    assert decompress_headers(cout, dout, GENESIS_HEADER)
+    assert dout.getvalue() == headers[HEADER_LEN:]
+
+
+def test_time_offset():
+    """
+    Check the time offset encoding at and around the limits of a 2 byte offset and of a
+    4 byte time, and through the codec.
+    """
+    logger.info(f"starting test_time_offset")
+
+    def pack(time):
+        return struct.pack("<I", time)
+
+    prev_time = 1_000_000
+    for delta in (MIN_INT, MIN_INT + 1, -1, 0, 1, MAX_INT - 1, MAX_INT):
+        offset = time_offset(pack(prev_time), pack(prev_time + delta))
+        assert offset == struct.pack("<h", delta), delta
+        assert apply_time_offset(pack(prev_time), offset) == pack(prev_time + delta), delta
+    for delta in (MIN_INT - 1, MAX_INT + 1, -prev_time, 2 ** 32 - 1 - prev_time):
+        assert time_offset(pack(prev_time), pack(prev_time + delta)) is None, delta
+
+    # Offsets taking the time outside 4 bytes only come from a bad encoder
+    assert apply_time_offset(pack(0), struct.pack("<h", 0)) == pack(0)
+    assert apply_time_offset(pack(2 ** 32 - 1), struct.pack("<h", 0)) == pack(2 ** 32 - 1)
+    for prev, delta in ((0, -1), (MAX_INT - 1, MIN_INT), (2 ** 32 - 1, 1), (2 ** 32 - 2, MAX_INT)):
+        try:
+            apply_time_offset(pack(prev), struct.pack("<h", delta))
+        except CompressionError:
+            continue
+        raise AssertionError(f"offset {delta} from {prev} accepted")
+
+    # Through the codec: only the deltas which fit are sent as 2 bytes
+    deltas = [MAX_INT, MAX_INT + 1, MIN_INT, MIN_INT - 1, 0]
+    headers = [GENESIS_HEADER]
+    (time,) = struct.unpack("<I", GENESIS_HEADER[68:72])
+    for delta in deltas:
+        time += delta
+        headers.append(child_header(headers[-1], time=pack(time)))
+    cout = BytesIO()
+    assert compress_headers(BytesIO(b"".join(headers)), cout)
+    compressed = cout.getvalue()
+    pos = 0
+    for delta in deltas:
+        assert bool(compressed[pos] & MASK_TIME) == (MIN_INT <= delta <= MAX_INT), delta
+        pos += 1 + record_length(compressed[pos])
+    cout.seek(0)
+    dout = BytesIO()
+    assert decompress_headers(cout, dout, GENESIS_HEADER, strict=True)
+    assert dout.getvalue() == b"".join(headers[1:])
+
+
+def test_first_full():
+    """
+    Run compression with first_full over a chain which returns to the version of the
+    header before it, and over a synthetic chain split into messages.
+    Asserting each message decodes whole against a zeroed previous header, independently
+    and in sequence, and that strict mode accepts the self contained first record.
+    """
+    logger.info(f"starting test_first_full")
+
+    header_1 = child_header(GENESIS_HEADER, version=struct.pack("<i", 2))
+    header_2 = child_header(header_1, version=GENESIS_HEADER[0:4])
+    headers = GENESIS_HEADER + header_1 + header_2
+    cout = BytesIO()
+    assert compress_headers(BytesIO(headers), cout, first_full=True)
+    compressed = cout.getvalue()
+    assert compressed[0] == NEW_DISTINCT_VERSION << 5
+    assert 1 + record_length(compressed[0]) == FULL_RECORD_LEN
+    # Genesis' version is not ours to refer to, so it is sent again
+    assert compressed[FULL_RECORD_LEN] >> 5 == NEW_DISTINCT_VERSION
+    for prev_header in (bytes(HEADER_LEN), GENESIS_HEADER):
+        dout = BytesIO()
+        assert decompress_headers(BytesIO(compressed), dout, prev_header, strict=True)
+        assert dout.getvalue() == headers[HEADER_LEN:]
+
+    headers = synthetic_chain(1000)
+    messages = []
+    for start in range(0, 1000, 100):
+        message = BytesIO()
+        batch = headers[start * HEADER_LEN : (start + 101) * HEADER_LEN]
+        assert compress_headers(BytesIO(batch), message, first_full=True)
+        messages.append(message.getvalue())
+    stateful = BytesIO()
+    assert compress_headers(BytesIO(headers), stateful)
+    assert sum(map(len, messages)) > stateful.tell()
+
+    # Independently, each against a header the receiver does not hold
+    for i, message in enumerate(messages):
+        dout = BytesIO()
+        assert decompress_headers(BytesIO(message), dout, bytes(HEADER_LEN), strict=True)
+        first, last = 100 * i + 1, 100 * i + 101
+        assert dout.getvalue() == headers[first * HEADER_LEN : last * HEADER_LEN]
+
+    # In sequence, each against the last header of the message before
+    prev_header = GENESIS_HEADER
+    decoded = BytesIO()
+    for message in messages:
+        dout = BytesIO()
+        assert decompress_headers(BytesIO(message), dout, prev_header, strict=True)
+        decoded.write(dout.getvalue())
+        prev_header = dout.getvalue()[-HEADER_LEN:]
+    assert decoded.getvalue() == headers[HEADER_LEN:]
+
+
+def test_adversarial_compression():
+    """
+    Run compression over random header sequences with extreme field values, repeated
+    headers and no chain linkage, then decompress the output.
+    Asserting compression never fails, no record is longer than a fully explicit one,
+    and decompression reproduces the input exactly.
+    """
+    logger.info(f"starting test_adversarial_compression")
+
+    rng = Random(1)
+    extremes = {
+        "version": [struct.pack("<i", v) for v in (-(2 ** 31), -1, 0, 1, 2 ** 31 - 1)],
+        "time": [struct.pack("<I", t) for t in (0, 1, MAX_INT, 2 ** 32 - 2, 2 ** 32 - 1)],
+        "n_bits": [bytes(4), b"\xff" * 4, GENESIS_HEADER[72:76]],
+        "nonce": [bytes(4), b"\xff" * 4],
+    }
+
+    def random_header(parent: bytes) -> bytes:
+        choice = rng.random()
+        if choice < 0.1:
+            # Identical to the previous header
+            return parent
+        fields = {
+            field: rng.choice(values) if rng.random() < 0.5 else random_bytes(rng, 4)
+            for field, values in extremes.items()
+        }
+        if choice < 0.5:
+            # Links to the previous header
+            return child_header(parent, **fields)
+        return random_bytes(rng, HEADER_LEN)
+
+    for _ in range(200):
+        headers = [GENESIS_HEADER]
+        for _ in range(rng.randint(1, 30)):
+            headers.append(random_header(headers[-1]))
+        cout = BytesIO()
+        assert compress_headers(BytesIO(b"".join(headers)), cout)
+
+        compressed = cout.getvalue()
+        pos = 0
+        while pos < len(compressed):
+            length = 1 + record_length(compressed[pos])
+            assert SPEC_MIN_RECORD_LEN <= length <= FULL_RECORD_LEN
+            pos += length
+        assert pos == len(compressed)
+
+        cout.seek(0)
+        dout = BytesIO()
+        assert decompress_headers(cout, dout, GENESIS_HEADER, strict=True)
+        assert dout.getvalue() == b"".join(headers[1:])
+
+
 def test_codec(partial=False):
     """
     Run a test of compression and decompression.
@@ -160,9 +960,19 @@ def test_codec(partial=False):
         logger.error(f"uncompressed input does not match decompressed output")
         return
 
+    saving = 1 - compressed_size / uncompressed_size
+    assert partial or abs(saving - SPEC_SAVING) <= SPEC_SAVING_TOLERANCE, (
+        f"compression saved {saving:.1%} of the chain, "
+        f"outside {SPEC_SAVING:.0%} ± {SPEC_SAVING_TOLERANCE:.0%} claimed by the spec"
+    )
+
     logger.info(f"compressed and decompressed {num_headers} headers in {round((time_end_compress - time_start_compress) + (t5 - t4), 2)} s")
     logger.info(f"compression saved {uncompressed_size - compressed_size:,} Bytes in total")
 
+    if not partial:
+        for flag, cost in field_contributions(cin).items():
+            logger.info(f"{flag} costs {cost:,} Bytes ({cost / uncompressed_size:.1%})")
+
 
 if __name__ == "__main__":
     parser = argparse.ArgumentParser(description='Test block header compression and decompression')
@@ -179,6 +989,24 @@ if __name__ == "__main__":
         SOURCE = Source.REST
         logger.info(f"using headers from bitcoind REST API")
 
+    test_genesis_hashes()
+    test_sanity()
+    test_tip_hash()
+    test_partial_input()
+    test_golden_vectors()
+    test_explicit_byte_order()
+    test_strict_canonical()
+    test_no_raise()
+    test_limits()
+    test_version_sentinel()
+    test_record_reads()
+    test_record_lengths()
+    test_disabled_optimisations()
+    test_spec_saving()
+    test_negative_versions()
+    test_time_offset()
+    test_first_full()
+    test_adversarial_compression()
     # Test 2000 headers from a random position in the chain
     test_codec(partial=True)
     # Test the entire chain